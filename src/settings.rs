@@ -3,11 +3,23 @@
 //! # GC State
 //!
 //! Users can control whether the GC will run, and whether deeper scopes
-//! can even re-enable the GC
+//! can even re-enable the GC. They can also set an advance-epoch threshold
+//! (how many pinned epochs have to accumulate before a collection is
+//! attempted) and a safepoint setting that requests a collection at the next
+//! pin. This crate has no pin or collection path: both settings are stored
+//! and read back through `GCSettings::effective_advance_threshold` and
+//! `GCSettings::take_safepoint`, and nothing here acts on them.
+//!
+//! `GCSettings` is built on `AtomicUsize` rather than `Cell`, so a single
+//! instance can be shared and tuned from every thread that participates in
+//! the epoch scheme, the same way an `Arc` stands in for an `Rc` once state
+//! needs to cross thread boundaries.
 
+use core::fmt;
 use core::fmt::Debug;
-use std::cell::Cell;
+use std::marker::PhantomData;
 use std::ops::Deref;
+use std::sync::atomic::{AtomicUsize, Ordering};
 
 
 /// Determines the strength of the setting
@@ -62,6 +74,42 @@ impl Stronger for Collect {
     }
 }
 
+/// The number of pinned epochs / deferred-garbage bags that must accumulate
+/// before a collection is attempted. A smaller threshold collects more
+/// eagerly.
+#[derive(PartialEq, Copy, Clone, Debug)]
+pub struct Threshold(pub u32);
+
+/// The threshold used by a fresh `GCSettings` when no scope has tightened it
+const DEFAULT_ADVANCE_THRESHOLD: u32 = 32;
+
+impl Stronger for Threshold {
+    #[inline]
+    fn stronger_than(&self, other: &Self) -> bool {
+        // A smaller threshold collects more eagerly, so it's the stronger setting
+        self.0 < other.0
+    }
+}
+
+/// Whether a scope wants an immediate collection attempt at the next pin,
+/// complementing the passive `Collect`/`NoCollect` gate.
+#[derive(PartialEq, Copy, Clone, Debug)]
+pub enum Safepoint {
+    Normal,
+    RequestCollect,
+}
+
+impl Stronger for Safepoint {
+    #[inline]
+    fn stronger_than(&self, _: &Self) -> bool {
+        // A request to collect dominates the normal, amortized behavior
+        match *self {
+            Safepoint::RequestCollect => true,
+            Safepoint::Normal => false,
+        }
+    }
+}
+
 #[inline]
 fn strongest<T: Stronger>(old: T, new: T) -> T {
     if old.stronger_than(&new) { old } else { new }
@@ -80,54 +128,247 @@ impl<T: Stronger + Clone + Copy + Debug> Deref for Setting<T> {
     }
 }
 
-macro_rules! generate_setting_fncs {
-    ($s:ident, $v:ident, $va: ident, $st:ident) => {{
-        let mut setting = $s.old.$v.get();
-        match setting.strength {
-            Strength::Lenient => {
-                setting.val = $va;
-                setting.strength = $st;
-            },
-            Strength::AsStrongAs(test) => {
-                setting.val = strongest($va, test);
-                setting.strength = strongest(setting.strength, $st);
-            },
-            Strength::Strict => {},
-        }
-        $s.cur.collect.set(setting);
-        $s
-    }};
-    ($s:ident, $v:ident, $va: ident) => {{
-        let mut setting = $s.old.$v.get();
-        match setting.strength {
-            Strength::Lenient => {
-                setting.val = $va;
-            },
-            Strength::AsStrongAs(test) => {
-                setting.val = strongest($va, test);
-            },
-            Strength::Strict => {},
+/// Values that are small enough to be packed, along with a `Strength`, into
+/// the two `AtomicUsize` words of an `AtomicSetting` so a `Setting` can be
+/// read and swapped without a lock.
+pub trait Packed: Stronger + Copy + Clone + Debug {
+    /// Encode this value into the low bits of a packed word
+    fn encode(self) -> usize;
+
+    /// Decode a value out of the low bits of a packed word
+    fn decode(bits: usize) -> Self;
+}
+
+impl Packed for Collect {
+    #[inline]
+    fn encode(self) -> usize {
+        match self {
+            Collect::NoCollect => 0,
+            Collect::Collect => 1,
         }
-        $s.cur.collect.set(setting);
-        $s
-    }};
+    }
+
+    #[inline]
+    fn decode(bits: usize) -> Self {
+        if bits & 1 == 1 { Collect::Collect } else { Collect::NoCollect }
+    }
 }
 
-/// This struct is a collection of available settings with a builder api
-#[derive(Clone, Debug)]
-pub struct GCSettings {
-    pub collect: Cell<Setting<Collect>>
+impl Packed for Threshold {
+    #[inline]
+    fn encode(self) -> usize {
+        self.0 as usize
+    }
+
+    #[inline]
+    fn decode(bits: usize) -> Self {
+        Threshold(bits as u32)
+    }
 }
 
-impl GCSettings {
-    pub fn new() -> GCSettings {
-        GCSettings {
-           collect: Cell::new(Setting {
-                val: Collect::Collect,
-                strength: Strength::Lenient,
-            })
+impl Packed for Safepoint {
+    #[inline]
+    fn encode(self) -> usize {
+        match self {
+            Safepoint::Normal => 0,
+            Safepoint::RequestCollect => 1,
         }
     }
+
+    #[inline]
+    fn decode(bits: usize) -> Self {
+        if bits & 1 == 1 { Safepoint::RequestCollect } else { Safepoint::Normal }
+    }
+}
+
+// A `Setting<T>` is packed into two words rather than one: `main` holds the
+// value plus the discriminant of its `Strength`, and `carried` holds the
+// value attached to `Strength::AsStrongAs`, when present. A single word
+// isn't wide enough for both: `T`s like `Threshold` use the full 32 bits of
+// `encode()`, and a value plus a same-sized carried value plus a strength
+// tag doesn't fit in a 64-bit `usize`. This assumes a 64-bit `usize`.
+const VAL_BITS: u32 = 32;
+const VAL_MASK: usize = (1 << VAL_BITS) - 1;
+const STRENGTH_SHIFT: u32 = VAL_BITS;
+const STRENGTH_MASK: usize = 0b11;
+
+#[inline]
+fn pack_main<T: Packed>(val: T, strength_tag: usize) -> usize {
+    (val.encode() & VAL_MASK) | (strength_tag << STRENGTH_SHIFT)
+}
+
+#[inline]
+fn unpack_main<T: Packed>(bits: usize) -> (T, usize) {
+    (T::decode(bits & VAL_MASK), (bits >> STRENGTH_SHIFT) & STRENGTH_MASK)
+}
+
+#[inline]
+fn pack<T: Packed>(setting: Setting<T>) -> (usize, usize) {
+    match setting.strength {
+        Strength::Lenient => (pack_main(setting.val, 0), 0),
+        Strength::AsStrongAs(carried) => (pack_main(setting.val, 1), carried.encode()),
+        Strength::Strict => (pack_main(setting.val, 2), 0),
+    }
+}
+
+#[inline]
+fn unpack<T: Packed>(main: usize, carried: usize) -> Setting<T> {
+    let (val, strength_tag) = unpack_main(main);
+    let strength = match strength_tag {
+        0 => Strength::Lenient,
+        1 => Strength::AsStrongAs(T::decode(carried)),
+        _ => Strength::Strict,
+    };
+    Setting { val: val, strength: strength }
+}
+
+/// A `Setting` that can be shared across threads: reads and writes go
+/// through a pair of `AtomicUsize`s rather than a `Cell`.
+pub struct AtomicSetting<T: Packed> {
+    main: AtomicUsize,
+    carried: AtomicUsize,
+    _marker: PhantomData<T>,
+}
+
+impl<T: Packed> AtomicSetting<T> {
+    fn new(setting: Setting<T>) -> AtomicSetting<T> {
+        let (main, carried) = pack(setting);
+        AtomicSetting {
+            main: AtomicUsize::new(main),
+            carried: AtomicUsize::new(carried),
+            _marker: PhantomData,
+        }
+    }
+
+    pub fn get(&self) -> Setting<T> {
+        // `carried` is only meaningful when `main` says the strength is
+        // `AsStrongAs`, so read it first: a writer racing between these two
+        // loads can only leave us with a stale `carried` paired with a fresh
+        // `main`, never the other way around. Callers re-`get()` on every
+        // CAS retry, so a torn read here just costs an extra loop iteration.
+        let carried = self.carried.load(Ordering::SeqCst);
+        let main = self.main.load(Ordering::SeqCst);
+        unpack(main, carried)
+    }
+
+    pub fn set(&self, setting: Setting<T>) {
+        let (main, carried) = pack(setting);
+        self.carried.store(carried, Ordering::SeqCst);
+        self.main.store(main, Ordering::SeqCst);
+    }
+
+    /// Stores `new` if the `main` word still equals that of `current`, and
+    /// returns whether it did. If another thread changed the setting first,
+    /// nothing is stored and `false` is returned; this does not retry, the
+    /// generated setters call it in a loop until it succeeds.
+    pub fn compare_and_set(&self, current: Setting<T>, new: Setting<T>) -> bool {
+        let (cur_main, _) = pack(current);
+        let (new_main, new_carried) = pack(new);
+        // Only touch `carried` once the CAS on `main` is known to apply;
+        // otherwise a losing attempt would clobber the live carried value
+        // with state nobody asked for.
+        match self.main.compare_exchange(cur_main, new_main, Ordering::SeqCst, Ordering::SeqCst) {
+            Ok(_) => {
+                self.carried.store(new_carried, Ordering::SeqCst);
+                true
+            },
+            Err(_) => false,
+        }
+    }
+}
+
+impl<T: Packed> Clone for AtomicSetting<T> {
+    fn clone(&self) -> AtomicSetting<T> {
+        AtomicSetting::new(self.get())
+    }
+}
+
+impl<T: Packed> Debug for AtomicSetting<T> {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        self.get().fmt(f)
+    }
+}
+
+// `$setter`/`$setter_strength` are spelled out by the caller, rather than
+// pasted together from `$name`, because stable `macro_rules!` has no way to
+// synthesize a new identifier (e.g. `with_` + `$name`) from an existing one.
+macro_rules! define_settings {
+    ($( $name:ident : $ty:ty = $default:expr => ($setter:ident, $setter_strength:ident) ),+ $(,)*) => {
+        /// This struct is a collection of available settings with a builder api
+        #[derive(Clone, Debug)]
+        pub struct GCSettings {
+            $(pub $name: AtomicSetting<$ty>),+
+        }
+
+        impl GCSettings {
+            pub fn new() -> GCSettings {
+                GCSettings {
+                    $($name: AtomicSetting::new(Setting {
+                        val: $default,
+                        strength: Strength::Lenient,
+                    })),+
+                }
+            }
+        }
+
+        impl<'a> ScopedGCSettings<'a> {
+            $(
+                pub fn $setter_strength(&'a self, val: $ty, strength: Strength<$ty>)
+                                        -> &ScopedGCSettings<'a> {
+                    loop {
+                        // Build the next setting on top of whatever is currently
+                        // in effect (which includes any narrowing this same
+                        // scope already applied), not the snapshot `old` took
+                        // when the scope was entered — `old` is kept only to
+                        // restore the parent's setting on `Drop`.
+                        let current = self.cur.$name.get();
+                        let mut setting = current;
+                        match setting.strength {
+                            Strength::Lenient => {
+                                setting.val = val;
+                                setting.strength = strength;
+                            },
+                            Strength::AsStrongAs(test) => {
+                                setting.val = strongest(val, test);
+                                setting.strength = strongest(setting.strength, strength);
+                            },
+                            Strength::Strict => {},
+                        }
+                        if self.cur.$name.compare_and_set(current, setting) {
+                            break;
+                        }
+                    }
+                    self
+                }
+
+                pub fn $setter(&'a self, val: $ty) -> &ScopedGCSettings<'a> {
+                    loop {
+                        let current = self.cur.$name.get();
+                        let mut setting = current;
+                        match setting.strength {
+                            Strength::Lenient => {
+                                setting.val = val;
+                            },
+                            Strength::AsStrongAs(test) => {
+                                setting.val = strongest(val, test);
+                            },
+                            Strength::Strict => {},
+                        }
+                        if self.cur.$name.compare_and_set(current, setting) {
+                            break;
+                        }
+                    }
+                    self
+                }
+            )+
+        }
+
+        impl<'a> Drop for ScopedGCSettings<'a> {
+            fn drop(&mut self) {
+                $(self.cur.$name.set(self.old.$name.get());)+
+            }
+        }
+    };
 }
 
 pub struct ScopedGCSettings<'a> {
@@ -142,15 +383,202 @@ impl<'a> ScopedGCSettings<'a> {
             cur: old,
         }
     }
+}
+
+define_settings! {
+    collect: Collect = Collect::Collect => (with_collect, with_collect_strength),
+    advance_threshold: Threshold = Threshold(DEFAULT_ADVANCE_THRESHOLD) => (with_advance_threshold, with_advance_threshold_strength),
+    safepoint: Safepoint = Safepoint::Normal => (with_safepoint, with_safepoint_strength),
+}
+
+impl GCSettings {
+    /// The advance-epoch threshold currently in effect.
+    ///
+    /// This crate has no pin or epoch-advance path, so nothing calls this
+    /// outside of the tests and the threshold does not change when a
+    /// collection is attempted.
+    pub fn effective_advance_threshold(&self) -> Threshold {
+        self.advance_threshold.get().val
+    }
+
+    /// Consumes a pending collection request: if the effective setting is
+    /// `Safepoint::RequestCollect`, resets it to `Normal` and returns
+    /// `RequestCollect`; otherwise returns `Normal` and leaves it alone.
+    /// This is a take, not a peek, so a request is reported by exactly one
+    /// call rather than by every call for the rest of the scope that made it.
+    ///
+    /// This crate has no pin path, so nothing calls this outside of the
+    /// tests and a request does not by itself trigger a collection.
+    pub fn take_safepoint(&self) -> Safepoint {
+        loop {
+            let current = self.safepoint.get();
+            if current.val == Safepoint::Normal {
+                return Safepoint::Normal;
+            }
+            let consumed = Setting {
+                val: Safepoint::Normal,
+                strength: current.strength,
+            };
+            if self.safepoint.compare_and_set(current, consumed) {
+                return current.val;
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
 
-    pub fn with_collect_strength(&'a self, val: Collect,
-                                strength: Strength<Collect>)
-                             -> &ScopedGCSettings<'a> {
-        generate_setting_fncs!(self, collect, val, strength)
+    #[test]
+    fn scoped_gc_settings_drop_restores_parent() {
+        let settings = GCSettings::new();
+        assert_eq!(settings.collect.get().val, Collect::Collect);
+
+        {
+            let scope = ScopedGCSettings::new(&settings);
+            scope.with_collect(Collect::NoCollect);
+            assert_eq!(settings.collect.get().val, Collect::NoCollect);
+        }
+
+        // Leaving the scope puts the parent's setting back, rather than
+        // leaving the mutation in place.
+        assert_eq!(settings.collect.get().val, Collect::Collect);
     }
 
-    pub fn with_collect(&'a self, val: Collect) -> &ScopedGCSettings<'a> {
-        generate_setting_fncs!(self, collect, val)
+    #[test]
+    fn atomic_setting_get_set_roundtrip() {
+        let atomic = AtomicSetting::new(Setting {
+            val: Collect::Collect,
+            strength: Strength::Lenient,
+        });
+        assert_eq!(atomic.get().val, Collect::Collect);
+
+        atomic.set(Setting {
+            val: Collect::NoCollect,
+            strength: Strength::Strict,
+        });
+        let after = atomic.get();
+        assert_eq!(after.val, Collect::NoCollect);
+        assert!(match after.strength {
+            Strength::Strict => true,
+            _ => false,
+        });
+    }
+
+    #[test]
+    fn atomic_setting_roundtrips_large_carried_threshold() {
+        // Regression test: a `Threshold` carried by `AsStrongAs` uses the
+        // full 32-bit range, which a single packed word can't also fit
+        // alongside the main value and strength tag.
+        let large = Threshold(1_500_000_000);
+        let atomic = AtomicSetting::new(Setting {
+            val: Threshold(DEFAULT_ADVANCE_THRESHOLD),
+            strength: Strength::AsStrongAs(large),
+        });
+        let roundtripped = atomic.get();
+        assert_eq!(roundtripped.val.0, DEFAULT_ADVANCE_THRESHOLD);
+        match roundtripped.strength {
+            Strength::AsStrongAs(carried) => assert_eq!(carried.0, large.0),
+            _ => panic!("expected AsStrongAs to roundtrip"),
+        }
+    }
+
+    #[test]
+    fn atomic_setting_failed_compare_and_set_does_not_clobber_carried() {
+        // Regression test: a losing CAS attempt must not overwrite the live
+        // carried value with the value it was attempting to install.
+        let atomic = AtomicSetting::new(Setting {
+            val: Threshold(DEFAULT_ADVANCE_THRESHOLD),
+            strength: Strength::AsStrongAs(Threshold(99)),
+        });
+        let stale = Setting {
+            val: Threshold(DEFAULT_ADVANCE_THRESHOLD + 1),
+            strength: Strength::AsStrongAs(Threshold(1)),
+        };
+
+        let applied = atomic.compare_and_set(stale, Setting {
+            val: Threshold(DEFAULT_ADVANCE_THRESHOLD),
+            strength: Strength::AsStrongAs(Threshold(7)),
+        });
+        assert!(!applied);
+
+        match atomic.get().strength {
+            Strength::AsStrongAs(carried) => assert_eq!(carried.0, 99),
+            _ => panic!("expected AsStrongAs to survive the failed CAS"),
+        }
     }
 
+    #[test]
+    fn atomic_setting_compare_and_set() {
+        let atomic = AtomicSetting::new(Setting {
+            val: Collect::Collect,
+            strength: Strength::Lenient,
+        });
+        let stale = atomic.get();
+
+        // A CAS against the current value succeeds and applies the update.
+        let applied = atomic.compare_and_set(stale, Setting {
+            val: Collect::NoCollect,
+            strength: Strength::Lenient,
+        });
+        assert!(applied);
+        assert_eq!(atomic.get().val, Collect::NoCollect);
+
+        // Retrying against the now-stale `current` fails without changing state.
+        let applied_again = atomic.compare_and_set(stale, Setting {
+            val: Collect::Collect,
+            strength: Strength::Lenient,
+        });
+        assert!(!applied_again);
+        assert_eq!(atomic.get().val, Collect::NoCollect);
+    }
+
+    #[test]
+    fn builder_strict_blocks_override() {
+        let settings = GCSettings::new();
+        {
+            let scope = ScopedGCSettings::new(&settings);
+            scope.with_collect_strength(Collect::NoCollect, Strength::Strict);
+            assert_eq!(settings.collect.get().val, Collect::NoCollect);
+
+            // A Strict setting can't be changed by further calls in the same scope.
+            scope.with_collect(Collect::Collect);
+            assert_eq!(settings.collect.get().val, Collect::NoCollect);
+        }
+        // Dropping the scope restores the parent's setting.
+        assert_eq!(settings.collect.get().val, Collect::Collect);
+    }
+
+    #[test]
+    fn builder_as_strong_as_narrows_threshold() {
+        let settings = GCSettings::new();
+        {
+            let scope = ScopedGCSettings::new(&settings);
+            scope.with_advance_threshold_strength(Threshold(16), Strength::AsStrongAs(Threshold(16)));
+
+            // A looser request (a larger, weaker threshold) can't widen it back out.
+            scope.with_advance_threshold(Threshold(64));
+            assert_eq!(settings.effective_advance_threshold().0, 16);
+
+            // A stronger request (a smaller threshold) is still allowed to narrow it further.
+            scope.with_advance_threshold(Threshold(4));
+            assert_eq!(settings.effective_advance_threshold().0, 4);
+        }
+        assert_eq!(settings.effective_advance_threshold().0, DEFAULT_ADVANCE_THRESHOLD);
+    }
+
+    #[test]
+    fn take_safepoint_fires_once() {
+        let settings = GCSettings::new();
+        assert_eq!(settings.take_safepoint(), Safepoint::Normal);
+
+        let scope = ScopedGCSettings::new(&settings);
+        scope.with_safepoint(Safepoint::RequestCollect);
+
+        // The first take observes and consumes the request...
+        assert_eq!(settings.take_safepoint(), Safepoint::RequestCollect);
+        // ...so a second pin in the same scope doesn't see it again.
+        assert_eq!(settings.take_safepoint(), Safepoint::Normal);
+    }
 }